@@ -0,0 +1,119 @@
+use crate::Error;
+
+/// The line ending used by an `.osu` file, detected from its contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+        }
+    }
+
+    fn detect(source: &str) -> Self {
+        if source.contains("\r\n") {
+            Self::CrLf
+        } else {
+            Self::Lf
+        }
+    }
+}
+
+/// One `[Section]` block of an `.osu` file: everything between its header line
+/// and the next header (or the end of the file).
+#[derive(Debug, Clone, Copy)]
+pub struct Section<'a> {
+    pub name: &'a str,
+    pub body: &'a str,
+    /// Byte offset of `body` within the original source.
+    pub body_start: usize,
+    /// 1-indexed line number of the first line of `body`.
+    pub start_line: usize,
+}
+
+impl<'a> Section<'a> {
+    pub fn body_end(&self) -> usize {
+        self.body_start + self.body.len()
+    }
+}
+
+/// An `.osu` file scanned into its `[Section]` blocks, tolerant of LF or CRLF
+/// line endings, blank lines, and trailing comment lines within a section.
+#[derive(Debug, Clone)]
+pub struct Beatmap<'a> {
+    pub line_ending: LineEnding,
+    sections: Vec<Section<'a>>,
+}
+
+impl<'a> Beatmap<'a> {
+    /// Scan `source` into its sections by looking for `[Header]` lines; any
+    /// content before the first header is discarded, matching the layout of
+    /// every real `.osu` file (the `osu file format v14` line aside).
+    pub fn parse(source: &'a str) -> Self {
+        let mut sections = Vec::new();
+        let mut current: Option<(&'a str, usize, usize)> = None;
+        let mut offset = 0;
+        let mut line_no = 0;
+        for raw_line in source.split_inclusive('\n') {
+            line_no += 1;
+            let content = raw_line.trim_end_matches(['\n', '\r']);
+            if let Some(name) = section_header(content) {
+                if let Some((name, body_start, start_line)) = current.take() {
+                    sections.push(Section {
+                        name,
+                        body: &source[body_start..offset],
+                        body_start,
+                        start_line,
+                    });
+                }
+                current = Some((name, offset + raw_line.len(), line_no + 1));
+            }
+            offset += raw_line.len();
+        }
+        if let Some((name, body_start, start_line)) = current.take() {
+            sections.push(Section {
+                name,
+                body: &source[body_start..],
+                body_start,
+                start_line,
+            });
+        }
+        Self {
+            line_ending: LineEnding::detect(source),
+            sections,
+        }
+    }
+
+    /// Look up a section by name, e.g. `beatmap.section("TimingPoints")`.
+    pub fn section(&self, name: &str) -> Result<Section<'a>, Error> {
+        self.sections
+            .iter()
+            .copied()
+            .find(|section| section.name == name)
+            .ok_or_else(|| Error::Parse {
+                line: 0,
+                reason: format!("no [{}] section found", name),
+            })
+    }
+}
+
+fn section_header(line: &str) -> Option<&str> {
+    let line = line.trim();
+    let inner = line.strip_prefix('[')?.strip_suffix(']')?;
+    Some(inner)
+}
+
+/// Splice `replacement` into `source` in place of `section`'s body, leaving
+/// everything outside the section untouched.
+pub fn splice_section(source: &str, section: &Section, replacement: &str) -> String {
+    let mut result = String::with_capacity(source.len());
+    result.push_str(&source[..section.body_start]);
+    result.push_str(replacement);
+    result.push_str(&source[section.body_end()..]);
+    result
+}
@@ -1,4 +1,6 @@
 use clap::{App, Arg};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
     error,
     fmt::{self, Display, Formatter},
@@ -6,14 +8,60 @@ use std::{
     path::{Path, PathBuf},
 };
 
+mod beatmap;
+
+use beatmap::{splice_section, Beatmap};
+
 #[derive(Debug)]
 enum Error {
     InvalidInput(String),
     OnFileOpen(io::Error),
     NoSetFolder,
+    Parse { line: usize, reason: String },
+    Failures(Vec<(PathBuf, Error)>),
+}
+
+const TIMING_SECTION: &str = "TimingPoints";
+
+/// The mute threshold used when `--mute_threshold` isn't given and there's no
+/// `--from` preset to fall back on
+const DEFAULT_MUTE_THRESHOLD: Volume = 5;
+
+/// Hitsound sample set (0 = inherit from the beatmap default, 1 = normal, 2 = soft, 3 = drum)
+type SampleSet = usize;
+
+/// Custom sample index (0 = use the sample set's default samples)
+type SampleIndex = usize;
+
+/// Bit of the timing point effects field that marks a kiai-time span
+const KIAI_BIT: usize = 1;
+
+/// Which extra fields, besides volume, should be read from the source and
+/// written to the targets; set by the `--copy-kiai` / `--copy-samples` flags
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct CopyOptions {
+    kiai: bool,
+    samples: bool,
 }
 
-const TIMING_HEADER: &str = "[TimingPoints]";
+/// How `--ramp`/`--ramp-snap` space out the interpolated ticks between two
+/// control points
+#[derive(Debug, Clone, Copy)]
+enum Ramp {
+    /// `--ramp <ms>`: a tick every `ms` milliseconds
+    Fixed(Time),
+    /// `--ramp-snap <divisor>`: a tick at every `1/divisor` beat of the
+    /// nearest preceding uninherited (red) line
+    Snap(u32),
+}
+
+/// The downbeat time and ms-per-beat of an uninherited (red) timing line,
+/// used to derive `--ramp-snap` tick positions
+#[derive(Debug, Clone, Copy)]
+struct BeatGrid {
+    offset: Time,
+    beat_len: f64,
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
@@ -24,6 +72,17 @@ impl Display for Error {
                 f,
                 "No set folder was found, try specifying a target file with --dest"
             ),
+            Self::Parse { line: 0, reason } => write!(f, "Parse error: {}", reason),
+            Self::Parse { line, reason } => {
+                write!(f, "Parse error at line {}: {}", line, reason)
+            }
+            Self::Failures(failures) => {
+                writeln!(f, "{} of the target diffs failed to update:", failures.len())?;
+                for (path, err) in failures {
+                    writeln!(f, "  {}: {}", path.display(), err)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -54,37 +113,79 @@ type Time = usize;
 /// Percent
 type Volume = usize;
 
-/// Parse the time and volume from a timing point
-fn parse_point(line: &str) -> (Time, Volume) {
-    let mut csv = line.split(',');
-    let time = csv.next().unwrap().parse().unwrap();
-    let volume = csv.nth(4).unwrap().parse().unwrap();
-    (time, volume)
+/// A control point read from a `[TimingPoints]` row: its volume, plus the
+/// kiai and hitsound sample state that `--copy-kiai` / `--copy-samples` can
+/// carry over alongside it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Point {
+    time: Time,
+    volume: Volume,
+    kiai: bool,
+    sample_set: SampleSet,
+    sample_index: SampleIndex,
 }
 
-/// Overwrite the time and volume of a timing point
-fn write_point(line: &str, point: (Time, Volume)) -> String {
-    let mut commas = line.char_indices().filter(|c| c.1 == ',').map(|c| c.0);
-    let after_time = commas.next().unwrap();
-    let before_volume = commas.nth(3).unwrap();
-    let after_volume = commas.next().unwrap();
-    let time_string = point.0.to_string();
-    let volume_string = point.1.to_string();
-    [
-        &time_string,
-        &line[after_time..=before_volume],
-        &volume_string,
-        &line[after_volume..],
-    ]
-    .concat()
+/// Parse one field of a CSV timing point row, reporting `line_no` (1-indexed)
+/// in any error so a malformed greenline can be found in the source file
+fn parse_field<T: std::str::FromStr>(
+    fields: &[&str],
+    idx: usize,
+    line_no: usize,
+    name: &str,
+) -> Result<T, Error> {
+    fields
+        .get(idx)
+        .and_then(|field| field.trim().parse().ok())
+        .ok_or_else(|| Error::Parse {
+            line: line_no,
+            reason: format!("expected {} in field {}", name, idx),
+        })
 }
 
-/// Split into (before_timing, timing, after_timing) where timing contains the
-/// timing points with no preceding or succeeding newlines
-fn extract_timing(source: &str) -> (&str, &str, &str) {
-    let start = source.find(TIMING_HEADER).unwrap() + TIMING_HEADER.len() + 2;
-    let end = start + source[start..].find("\r\n\r\n").unwrap();
-    (&source[..start], &source[start..end], &source[end..])
+/// Parse a timing point row into its time, volume, kiai state, and hitsound
+/// sample set/index
+fn parse_point(line: &str, line_no: usize) -> Result<Point, Error> {
+    let fields: Vec<_> = line.split(',').collect();
+    let time = parse_field(&fields, 0, line_no, "a time")?;
+    let sample_set = parse_field(&fields, 3, line_no, "a sample set")?;
+    let sample_index = parse_field(&fields, 4, line_no, "a sample index")?;
+    let volume = parse_field(&fields, 5, line_no, "a volume")?;
+    let effects: usize = parse_field(&fields, 7, line_no, "an effects bitmask")?;
+    Ok(Point {
+        time,
+        volume,
+        kiai: effects & KIAI_BIT != 0,
+        sample_set,
+        sample_index,
+    })
+}
+
+/// Overwrite the time and volume of a timing point, along with whichever of
+/// its kiai state and hitsound samples `copy` says to carry over
+fn write_point(line: &str, point: Point, copy: CopyOptions) -> String {
+    let mut fields: Vec<String> = line.split(',').map(str::to_owned).collect();
+    fields[0] = point.time.to_string();
+    fields[5] = point.volume.to_string();
+    if copy.samples {
+        fields[3] = point.sample_set.to_string();
+        fields[4] = point.sample_index.to_string();
+    }
+    if copy.kiai {
+        let effects: usize = fields[7].trim().parse().unwrap_or(0);
+        let effects = if point.kiai {
+            effects | KIAI_BIT
+        } else {
+            effects & !KIAI_BIT
+        };
+        fields[7] = effects.to_string();
+    }
+    fields.join(",")
+}
+
+/// Skip blank lines and `//` comments when walking a section body
+fn is_data_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    !trimmed.is_empty() && !trimmed.starts_with("//")
 }
 
 /// Convert an uninherited line to an inherited line with default effects
@@ -104,76 +205,197 @@ fn same_after_time(line1: &mut String, line2: &mut String) -> bool {
     line1[idx1..] == line2[idx2..]
 }
 
-/// Check if two timing points have the same volume
-fn same_volume(
-    point1: &mut (Time, Volume),
-    point2: &mut (Time, Volume),
-) -> bool {
-    point1.1 == point2.1
+/// Check if two timing points are indistinguishable along whichever fields
+/// `copy` says are being carried over, ignoring their timestamps
+fn same_point(copy: CopyOptions) -> impl FnMut(&mut Point, &mut Point) -> bool {
+    move |point1, point2| {
+        point1.volume == point2.volume
+            && (!copy.kiai || point1.kiai == point2.kiai)
+            && (!copy.samples
+                || (point1.sample_set, point1.sample_index)
+                    == (point2.sample_set, point2.sample_index))
+    }
 }
 
+/// Parse the downbeat time and beat length of every uninherited (red) line in
+/// a `[TimingPoints]` body, for deriving `--ramp-snap` tick positions
+fn parse_beat_grids(body: &str, start_line: usize) -> Result<Vec<BeatGrid>, Error> {
+    let mut grids = Vec::new();
+    for (offset, line) in body.lines().enumerate().filter(|(_, l)| is_data_line(l)) {
+        let fields: Vec<_> = line.split(',').collect();
+        let line_no = start_line + offset;
+        let uninherited: u8 = parse_field(&fields, 6, line_no, "an uninherited flag")?;
+        if uninherited == 1 {
+            let beat_len: f64 = parse_field(&fields, 1, line_no, "a beat length")?;
+            if beat_len <= 0.0 {
+                return Err(Error::Parse {
+                    line: line_no,
+                    reason: format!("expected a positive beat length, got {}", beat_len),
+                });
+            }
+            grids.push(BeatGrid {
+                offset: parse_field(&fields, 0, line_no, "a time")?,
+                beat_len,
+            });
+        }
+    }
+    Ok(grids)
+}
+
+/// The beat grid whose red line most recently precedes `time`, if any
+fn beat_grid_at(grids: &[BeatGrid], time: Time) -> Option<BeatGrid> {
+    grids.iter().rev().find(|grid| grid.offset <= time).copied()
+}
+
+/// The snap positions strictly between `t0` and `t1` that `ramp` would place
+/// a tick at
+fn ramp_ticks(t0: Time, t1: Time, ramp: Ramp, grids: &[BeatGrid]) -> Vec<Time> {
+    match ramp {
+        Ramp::Fixed(step) => (1..)
+            .map(|k| t0 + k * step)
+            .take_while(|&t| t < t1)
+            .collect(),
+        Ramp::Snap(divisor) => {
+            let grid = match beat_grid_at(grids, t0) {
+                Some(grid) => grid,
+                None => return Vec::new(),
+            };
+            let tick_len = grid.beat_len / f64::from(divisor);
+            let first_tick = ((t0 - grid.offset) as f64 / tick_len).floor() as i64 + 1;
+            (first_tick..)
+                .map(|n| grid.offset as f64 + n as f64 * tick_len)
+                .take_while(|&t| t < t1 as f64)
+                .map(|t| t.round() as Time)
+                .filter(|&t| t > t0 && t < t1)
+                .collect()
+        }
+    }
+}
+
+/// Interpolate `ramp`'s intermediate ticks between every consecutive pair of
+/// `points`, rounding each tick's volume to the nearest whole percent
+fn insert_ramp(points: Vec<Point>, ramp: Ramp, grids: &[BeatGrid]) -> Vec<Point> {
+    let mut result = Vec::with_capacity(points.len());
+    for pair in points.windows(2) {
+        let (p0, p1) = (pair[0], pair[1]);
+        result.push(p0);
+        for time in ramp_ticks(p0.time, p1.time, ramp, grids) {
+            let progress = (time - p0.time) as f64 / (p1.time - p0.time) as f64;
+            let volume = p0.volume as f64 + (p1.volume as f64 - p0.volume as f64) * progress;
+            result.push(Point {
+                time,
+                volume: volume.round() as Volume,
+                ..p0
+            });
+        }
+    }
+    if let Some(&last) = points.last() {
+        result.push(last);
+    }
+    result
+}
+
+/// A `VolumeCurve` written out to a standalone JSON file by `--export`, and
+/// read back by `--from` without the original source diff present
+#[derive(Debug, Serialize, Deserialize)]
+struct Preset {
+    mute_threshold: Volume,
+    copy: CopyOptions,
+    points: Vec<Point>,
+}
+
+#[derive(Debug)]
 struct VolumeCurve {
-    points: Vec<(Time, Volume)>,
+    points: Vec<Point>,
+    copy: CopyOptions,
 }
 
 impl VolumeCurve {
-    fn parse(source: &str, mute_threshold: Volume) -> Self {
-        let (_, timing, _) = extract_timing(source);
-        let mut points: Vec<_> = timing
-            .lines()
-            .map(parse_point)
-            .filter(|point| point.1 > mute_threshold)
-            .collect();
-        points.dedup_by(same_volume);
-        Self { points }
-    }
-
-    fn load<P>(source: P, mute_threshold: Volume) -> Result<Self, Error>
+    fn parse(
+        source: &str,
+        mute_threshold: Volume,
+        copy: CopyOptions,
+        ramp: Option<Ramp>,
+    ) -> Result<Self, Error> {
+        let timing = Beatmap::parse(source).section(TIMING_SECTION)?;
+        let mut points = Vec::new();
+        let data_lines = timing.body.lines().enumerate().filter(|(_, l)| is_data_line(l));
+        for (offset, line) in data_lines {
+            let point = parse_point(line, timing.start_line + offset)?;
+            if point.volume > mute_threshold {
+                points.push(point);
+            }
+        }
+        points.dedup_by(same_point(copy));
+        if let Some(ramp) = ramp {
+            let grids = parse_beat_grids(timing.body, timing.start_line)?;
+            points = insert_ramp(points, ramp, &grids);
+            points.dedup_by(same_point(copy));
+        }
+        Ok(Self { points, copy })
+    }
+
+    fn load<P>(
+        source: P,
+        mute_threshold: Volume,
+        copy: CopyOptions,
+        ramp: Option<Ramp>,
+    ) -> Result<Self, Error>
     where
         P: AsRef<Path>,
     {
         let source = fs::read_to_string(source).map_err(Error::OnFileOpen)?;
-        Ok(Self::parse(&source, mute_threshold))
+        Self::parse(&source, mute_threshold, copy, ramp)
     }
 
-    fn apply(&self, source: &str, mute_threshold: Volume) -> String {
+    fn apply(&self, source: &str, mute_threshold: Volume) -> Result<String, Error> {
         if self.points.is_empty() {
-            return source.to_owned();
+            return Ok(source.to_owned());
         }
-        let (before_timing, timing, after_timing) = extract_timing(source);
+        let beatmap = Beatmap::parse(source);
+        let timing = beatmap.section(TIMING_SECTION)?;
         let mut new_timing = Vec::new();
         let mut write_idx = 0;
         let mut current_volume = 100;
         let mut last_line = "";
-        for line in timing.lines() {
-            let old_point = parse_point(line);
+        for (offset, line) in timing.body.lines().enumerate() {
+            if !is_data_line(line) {
+                new_timing.push(line.to_owned());
+                continue;
+            }
+            let old_point = parse_point(line, timing.start_line + offset)?;
             while write_idx < self.points.len()
-                && self.points[write_idx].0 < old_point.0
+                && self.points[write_idx].time < old_point.time
             {
                 if !last_line.is_empty() {
                     new_timing.push(write_point(
                         &make_inherited(last_line),
                         self.points[write_idx],
+                        self.copy,
                     ));
                 }
-                current_volume = self.points[write_idx].1;
+                current_volume = self.points[write_idx].volume;
                 write_idx += 1;
             }
             if write_idx < self.points.len()
-                && self.points[write_idx].0 == old_point.0
+                && self.points[write_idx].time == old_point.time
             {
-                new_timing.push(write_point(line, self.points[write_idx]));
-                current_volume = self.points[write_idx].1;
+                new_timing.push(write_point(line, self.points[write_idx], self.copy));
+                current_volume = self.points[write_idx].volume;
                 write_idx += 1;
             } else {
-                let new_volume = if old_point.1 > mute_threshold {
+                let new_volume = if old_point.volume > mute_threshold {
                     current_volume
                 } else {
-                    old_point.1
+                    old_point.volume
                 };
                 new_timing.push(write_point(
                     &make_inherited(line),
-                    (old_point.0, new_volume),
+                    Point {
+                        volume: new_volume,
+                        ..old_point
+                    },
+                    self.copy,
                 ));
             }
             last_line = line;
@@ -182,12 +404,18 @@ impl VolumeCurve {
             new_timing.push(write_point(
                 &make_inherited(last_line),
                 self.points[write_idx],
+                self.copy,
             ));
             write_idx += 1;
         }
         new_timing.dedup_by(same_after_time);
-        let new_timing = new_timing.join("\r\n");
-        [before_timing, &new_timing, after_timing].concat()
+        let mut new_timing = new_timing.join(beatmap.line_ending.as_str());
+        // `lines()` strips the body's own trailing line ending, so `join` alone
+        // would drop it; restore it so only the timing points themselves change.
+        if timing.body.ends_with('\n') {
+            new_timing.push_str(beatmap.line_ending.as_str());
+        }
+        Ok(splice_section(source, &timing, &new_timing))
     }
 
     fn write<P>(&self, dest: P, mute_threshold: Volume) -> Result<(), Error>
@@ -195,101 +423,328 @@ impl VolumeCurve {
         P: AsRef<Path>,
     {
         let contents = fs::read_to_string(&dest).map_err(Error::OnFileOpen)?;
-        fs::write(dest, self.apply(&contents, mute_threshold))
-            .map_err(Error::OnFileOpen)
+        let written = self.apply(&contents, mute_threshold)?;
+        fs::write(dest, written).map_err(Error::OnFileOpen)
+    }
+
+    /// Parse a curve previously serialized by `to_preset_json`, without
+    /// needing the original source diff it was parsed from; also returns the
+    /// mute threshold it was exported with, for callers that don't override it
+    fn from_preset_json(json: &str) -> Result<(Self, Volume), Error> {
+        let preset: Preset = serde_json::from_str(json)
+            .map_err(|err| Error::InvalidInput(format!("could not read preset: {}", err)))?;
+        Ok((
+            Self {
+                points: preset.points,
+                copy: preset.copy,
+            },
+            preset.mute_threshold,
+        ))
+    }
+
+    /// Load a curve previously written out by `export`
+    fn import<P>(source: P) -> Result<(Self, Volume), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let contents = fs::read_to_string(source).map_err(Error::OnFileOpen)?;
+        Self::from_preset_json(&contents)
+    }
+
+    /// Serialize this curve to a standalone JSON preset, so it can be shared
+    /// or version-controlled and later reapplied with `import` / `--from`
+    fn to_preset_json(&self, mute_threshold: Volume) -> Result<String, Error> {
+        let preset = Preset {
+            mute_threshold,
+            copy: self.copy,
+            points: self.points.clone(),
+        };
+        serde_json::to_string_pretty(&preset)
+            .map_err(|err| Error::InvalidInput(format!("could not write preset: {}", err)))
+    }
+
+    /// Write this curve out to a standalone JSON file; see `to_preset_json`
+    fn export<P>(&self, dest: P, mute_threshold: Volume) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let json = self.to_preset_json(mute_threshold)?;
+        fs::write(dest, json).map_err(Error::OnFileOpen)
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
 
-fn main() -> Result<(), Error> {
+fn run() -> Result<(), Error> {
     let matches = App::new("osu-volume")
         .version("1.0")
         .author("Luminiscental <luminiscental01@gmail.com>")
         .about("Copy the volume curve from one difficulty of an osu map to other difficulties in the set.")
         .arg(Arg::with_name("source").help("The .osu file to copy the volume curve from.").required(true))
         .arg(Arg::with_name("dest").long("dest").takes_value(true).help("Optionally specify a specific .osu file to copy the volume curve to. If not present this defaults to all other diffs in the beatmapset."))
-        .arg(Arg::with_name("mute_threshold").long("mute_threshold").takes_value(true).help("Ignore greenlines with volumes less than or equal to this (treat them as muting sliderends).").default_value("5"))
+        .arg(Arg::with_name("mute_threshold").long("mute_threshold").takes_value(true).help("Ignore greenlines with volumes less than or equal to this (treat them as muting sliderends). Defaults to 5, or to the threshold stored in the preset when using --from."))
+        .arg(Arg::with_name("copy_kiai").long("copy-kiai").help("Also copy kiai time spans from the source diff's greenlines to the targets."))
+        .arg(Arg::with_name("copy_samples").long("copy-samples").help("Also copy each greenline's hitsound sample set and custom sample index from the source diff to the targets."))
+        .arg(Arg::with_name("ramp").long("ramp").takes_value(true).conflicts_with("ramp_snap").help("Interpolate a gradual volume fade between control points, inserting a greenline every <ms> milliseconds."))
+        .arg(Arg::with_name("ramp_snap").long("ramp-snap").takes_value(true).conflicts_with("ramp").help("Interpolate a gradual volume fade between control points, inserting a greenline at every 1/<divisor> beat of the preceding red line."))
+        .arg(Arg::with_name("export").long("export").takes_value(true).help("Write the volume curve out to <file> as a portable JSON preset, in addition to applying it to the target diffs."))
+        .arg(Arg::with_name("from").long("from").takes_value(true).conflicts_with_all(&["ramp", "ramp_snap", "copy_kiai", "copy_samples"]).help("Load the volume curve from a JSON preset written by --export instead of reading it from the source diff's greenlines. <source> is then used as the single target diff, unless --dest is also given."))
         .get_matches();
     let source = PathBuf::from(matches.value_of("source").unwrap());
-    let mute_threshold = matches
+    let mute_threshold_arg = matches
         .value_of("mute_threshold")
-        .unwrap()
-        .parse()
-        .map_err(|err| {
-            Error::InvalidInput(format!(
-                "Expected integer for volume threshold: {}",
-                err
-            ))
+        .map(|value| {
+            value.parse().map_err(|err| {
+                Error::InvalidInput(format!("Expected integer for volume threshold: {}", err))
+            })
+        })
+        .transpose()?;
+    let copy = CopyOptions {
+        kiai: matches.is_present("copy_kiai"),
+        samples: matches.is_present("copy_samples"),
+    };
+    let ramp = if let Some(ms) = matches.value_of("ramp") {
+        let ms = ms
+            .parse()
+            .map_err(|err| Error::InvalidInput(format!("Expected integer for --ramp: {}", err)))?;
+        if ms == 0 {
+            return Err(Error::InvalidInput("--ramp must be nonzero".to_owned()));
+        }
+        Some(Ramp::Fixed(ms))
+    } else if let Some(divisor) = matches.value_of("ramp_snap") {
+        let divisor = divisor.parse().map_err(|err| {
+            Error::InvalidInput(format!("Expected integer for --ramp-snap: {}", err))
         })?;
+        if divisor == 0 {
+            return Err(Error::InvalidInput(
+                "--ramp-snap divisor must be nonzero".to_owned(),
+            ));
+        }
+        Some(Ramp::Snap(divisor))
+    } else {
+        None
+    };
     let targets = if let Some(dest) = matches.value_of("dest") {
         vec![PathBuf::from(dest)]
+    } else if matches.is_present("from") {
+        vec![source.clone()]
     } else {
         find_siblings(&source)?
     };
-    let volume_curve = VolumeCurve::load(source, mute_threshold)?;
-    for target in targets {
-        volume_curve.write(target, mute_threshold)?;
+    let (volume_curve, mute_threshold) = if let Some(preset) = matches.value_of("from") {
+        let (volume_curve, preset_threshold) = VolumeCurve::import(preset)?;
+        (volume_curve, mute_threshold_arg.unwrap_or(preset_threshold))
+    } else {
+        let mute_threshold = mute_threshold_arg.unwrap_or(DEFAULT_MUTE_THRESHOLD);
+        (
+            VolumeCurve::load(source, mute_threshold, copy, ramp)?,
+            mute_threshold,
+        )
+    };
+    if let Some(export) = matches.value_of("export") {
+        volume_curve.export(export, mute_threshold)?;
+    }
+    let failures: Vec<(PathBuf, Error)> = targets
+        .into_par_iter()
+        .filter_map(|target| {
+            volume_curve
+                .write(&target, mute_threshold)
+                .err()
+                .map(|err| (target, err))
+        })
+        .collect();
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Failures(failures))
     }
-    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Build a point with just a time and volume, as most tests only care about those
+    fn point(time: Time, volume: Volume) -> Point {
+        Point {
+            time,
+            volume,
+            kiai: false,
+            sample_set: 0,
+            sample_index: 0,
+        }
+    }
+
     #[test]
     fn parse_point_works() {
-        assert_eq!(parse_point("95,517.241379310345,4,2,1,50,1,0"), (95, 50));
+        assert_eq!(
+            parse_point("95,517.241379310345,4,2,1,50,1,0", 1).unwrap(),
+            Point {
+                time: 95,
+                volume: 50,
+                kiai: false,
+                sample_set: 2,
+                sample_index: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_point_reads_kiai() {
+        assert!(parse_point("95,517.241379310345,4,2,1,50,1,1", 1).unwrap().kiai);
+    }
+
+    #[test]
+    fn parse_point_reports_short_rows() {
+        match parse_point("95", 3) {
+            Err(Error::Parse { line: 3, .. }) => (),
+            other => panic!("expected a parse error at line 3, got {:?}", other),
+        }
     }
 
     #[test]
     fn write_point_works() {
         assert_eq!(
-            write_point("15,326.086956521739,4,2,0,30,1,0", (10, 70)),
+            write_point(
+                "15,326.086956521739,4,2,0,30,1,0",
+                point(10, 70),
+                CopyOptions::default()
+            ),
             "10,326.086956521739,4,2,0,70,1,0"
         );
     }
 
+    #[test]
+    fn write_point_copies_kiai_and_samples() {
+        let copy = CopyOptions {
+            kiai: true,
+            samples: true,
+        };
+        let target_point = Point {
+            time: 10,
+            volume: 70,
+            kiai: true,
+            sample_set: 3,
+            sample_index: 5,
+        };
+        assert_eq!(
+            write_point("15,326.086956521739,4,2,0,30,1,0", target_point, copy),
+            "10,326.086956521739,4,3,5,70,1,1"
+        );
+    }
+
     #[test]
     fn volume_curve_parses() {
         let source = include_str!("testdiff.in");
-        let volume_curve = VolumeCurve::parse(source, 5);
+        let volume_curve = VolumeCurve::parse(source, 5, CopyOptions::default(), None).unwrap();
         assert_eq!(
             volume_curve.points,
             vec![
-                (15, 30),
-                (1319, 20),
-                (1563, 15),
-                (1808, 10),
-                (2053, 50),
-                (2623, 20)
+                point(15, 30),
+                point(1319, 20),
+                point(1563, 15),
+                point(1808, 10),
+                point(2053, 50),
+                point(2623, 20)
             ]
         );
     }
 
+    #[test]
+    fn volume_curve_parse_requires_timing_points() {
+        match VolumeCurve::parse(
+            "[General]\nAudioFilename: a.mp3\n",
+            5,
+            CopyOptions::default(),
+            None,
+        ) {
+            Err(Error::Parse { line: 0, .. }) => (),
+            other => panic!("expected a missing-section error, got {:?}", other),
+        }
+    }
+
     #[test]
     fn self_volume_curve_identity() {
         let source = include_str!("testdiff.in");
-        let application = VolumeCurve::parse(source, 5).apply(&source, 5);
+        let application = VolumeCurve::parse(source, 5, CopyOptions::default(), None)
+            .unwrap()
+            .apply(&source, 5)
+            .unwrap();
         assert_eq!(application, source);
     }
 
     #[test]
-    fn empty_volume_curve_identity() {
-        let source = include_str!("testdiff.in");
+    fn ramp_ticks_fixed_step() {
         assert_eq!(
-            VolumeCurve { points: Vec::new() }.apply(&source, 5),
-            source
+            ramp_ticks(0, 100, Ramp::Fixed(25), &[]),
+            vec![25, 50, 75]
+        );
+    }
+
+    #[test]
+    fn ramp_ticks_snap_to_beat_grid() {
+        let grids = [BeatGrid {
+            offset: 0,
+            beat_len: 500.0,
+        }];
+        assert_eq!(
+            ramp_ticks(100, 600, Ramp::Snap(4), &grids),
+            vec![125, 250, 375, 500]
+        );
+    }
+
+    #[test]
+    fn parse_beat_grids_rejects_non_positive_beat_length() {
+        match parse_beat_grids("0,0,4,2,1,100,1,1", 1) {
+            Err(Error::Parse { line: 1, .. }) => (),
+            other => panic!("expected a parse error at line 1, got {:?}", other),
+        }
+        match parse_beat_grids("0,-50,4,2,1,100,1,1", 1) {
+            Err(Error::Parse { line: 1, .. }) => (),
+            other => panic!("expected a parse error at line 1, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn insert_ramp_interpolates_volume() {
+        let points = vec![point(0, 0), point(100, 100)];
+        let ramped = insert_ramp(points, Ramp::Fixed(25), &[]);
+        assert_eq!(
+            ramped,
+            vec![
+                point(0, 0),
+                point(25, 25),
+                point(50, 50),
+                point(75, 75),
+                point(100, 100),
+            ]
         );
     }
 
+    #[test]
+    fn empty_volume_curve_identity() {
+        let source = include_str!("testdiff.in");
+        let curve = VolumeCurve {
+            points: Vec::new(),
+            copy: CopyOptions::default(),
+        };
+        assert_eq!(curve.apply(&source, 5).unwrap(), source);
+    }
+
     #[test]
     fn volume_curve_idempotent() {
         let curve = VolumeCurve {
-            points: vec![(1, 20), (998, 80), (3011, 45)],
+            points: vec![point(1, 20), point(998, 80), point(3011, 45)],
+            copy: CopyOptions::default(),
         };
         let source = include_str!("testdiff.in");
-        let once = curve.apply(&source, 5);
-        let twice = curve.apply(&once, 5);
+        let once = curve.apply(&source, 5).unwrap();
+        let twice = curve.apply(&once, 5).unwrap();
         assert_eq!(once, twice);
     }
 
@@ -297,19 +752,45 @@ mod tests {
     fn volume_curve_applies() {
         let curve = VolumeCurve {
             points: vec![
-                (5, 100),
-                (8, 10),
-                (15, 20),
-                (101, 30),
-                (1400, 20),
-                (1563, 15),
-                (2053, 100),
-                (2417, 30),
-                (3000, 50),
+                point(5, 100),
+                point(8, 10),
+                point(15, 20),
+                point(101, 30),
+                point(1400, 20),
+                point(1563, 15),
+                point(2053, 100),
+                point(2417, 30),
+                point(3000, 50),
             ],
+            copy: CopyOptions::default(),
         };
         let source = include_str!("testdiff.in");
         let expected = include_str!("testdiff_output.in");
-        assert_eq!(curve.apply(&source, 5), expected);
+        assert_eq!(curve.apply(&source, 5).unwrap(), expected);
+    }
+
+    #[test]
+    fn preset_round_trips_through_json() {
+        let curve = VolumeCurve {
+            points: vec![point(1, 20), point(998, 80), point(3011, 45)],
+            copy: CopyOptions {
+                kiai: true,
+                samples: false,
+            },
+        };
+        let json = curve.to_preset_json(50).unwrap();
+        let (imported, mute_threshold) = VolumeCurve::from_preset_json(&json).unwrap();
+        assert_eq!(mute_threshold, 50);
+        assert_eq!(imported.points, curve.points);
+        assert_eq!(imported.copy.kiai, curve.copy.kiai);
+        assert_eq!(imported.copy.samples, curve.copy.samples);
+    }
+
+    #[test]
+    fn from_preset_json_reports_malformed_input() {
+        match VolumeCurve::from_preset_json("not valid json") {
+            Err(Error::InvalidInput(_)) => (),
+            other => panic!("expected an invalid-input error, got {:?}", other),
+        }
     }
 }